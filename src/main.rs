@@ -1,36 +1,102 @@
 #![forbid(unsafe_code)]
 
 use std::{
+    collections::HashSet,
     fs::File,
     io,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, Write},
     net::SocketAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
     time::Duration,
 };
 
 use axum::{
-    extract::{Query, State},
+    extract::{Path as AxumPath, Query, State},
+    http::{HeaderMap, StatusCode},
     routing::get,
     Json, Router,
 };
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use hex::FromHexError;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use rocksdb::{
-    properties::ESTIMATE_NUM_KEYS, BlockBasedOptions, Cache, DBCompressionType, Options,
-    SliceTransform, DB,
+    properties::ESTIMATE_NUM_KEYS, BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor,
+    DBCompactionStyle, DBCompressionType, Direction, IteratorMode, Options, SliceTransform, DB,
 };
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use thiserror::Error;
 use tikv_jemallocator::Jemalloc;
-use tokio::{net::TcpListener, time::sleep};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+    time::{sleep, sleep_until, Instant},
+};
 
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    humantime::parse_duration(s).map_err(|err| err.to_string())
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum CompressionArg {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionArg {
+    fn to_db(self) -> DBCompressionType {
+        match self {
+            CompressionArg::None => DBCompressionType::None,
+            CompressionArg::Lz4 => DBCompressionType::Lz4,
+            CompressionArg::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum CompactionStyleArg {
+    Level,
+    Universal,
+}
+
+impl CompactionStyleArg {
+    fn to_db(self) -> DBCompactionStyle {
+        match self {
+            CompactionStyleArg::Level => DBCompactionStyle::Level,
+            CompactionStyleArg::Universal => DBCompactionStyle::Universal,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum HashKind {
+    Sha1,
+    Ntlm,
+}
+
+impl HashKind {
+    fn cf_name(self) -> &'static str {
+        match self {
+            HashKind::Sha1 => rocksdb::DEFAULT_COLUMN_FAMILY_NAME,
+            HashKind::Ntlm => NTLM_CF,
+        }
+    }
+
+    fn hash_byte_len(self) -> usize {
+        match self {
+            HashKind::Sha1 => 20,
+            HashKind::Ntlm => 16,
+        }
+    }
+}
+
 #[derive(Parser)]
 struct Opt {
     #[arg(long, default_value = "_db")]
@@ -45,8 +111,31 @@ struct Opt {
     bind: Option<SocketAddr>,
     #[arg(long, default_value = "268435456")]
     cache_bytes: usize,
+    #[arg(long, default_value = "16")]
+    update_concurrency: usize,
+    #[arg(long, default_value = "2s", value_parser = parse_duration)]
+    update_interval: Duration,
+    #[arg(long, default_value = "20ms", value_parser = parse_duration)]
+    update_rate_limit: Duration,
+    #[arg(long, value_enum, default_value = "lz4")]
+    compression: CompressionArg,
+    #[arg(long, value_enum, default_value = "zstd")]
+    bottommost_compression: CompressionArg,
+    #[arg(long, value_enum, default_value = "level")]
+    compaction_style: CompactionStyleArg,
+    #[arg(long, conflicts_with_all = ["source", "compact", "upstream_update"])]
+    open_read_only: bool,
+    #[arg(long)]
+    export: Option<PathBuf>,
+    #[arg(long)]
+    verify: bool,
+    #[arg(long, value_enum, default_value = "sha1")]
+    hash_type: HashKind,
 }
 
+const ETAG_CF: &str = "etags";
+const NTLM_CF: &str = "ntlm";
+
 struct Database {
     inner: DB,
 }
@@ -68,37 +157,150 @@ impl Database {
         db_opts.create_missing_column_families(true);
 
         db_opts.set_block_based_table_factory(&table_opts);
-        db_opts.set_compression_type(DBCompressionType::Lz4);
-        db_opts.set_bottommost_compression_type(DBCompressionType::Zstd);
+        db_opts.set_compression_type(opt.compression.to_db());
+        db_opts.set_bottommost_compression_type(opt.bottommost_compression.to_db());
+        db_opts.set_compaction_style(opt.compaction_style.to_db());
         db_opts.set_level_compaction_dynamic_level_bytes(false);
         db_opts.set_prefix_extractor(SliceTransform::create_noop());
 
-        let inner = DB::open(&db_opts, &opt.db)?;
+        let cfs = vec![
+            ColumnFamilyDescriptor::new(rocksdb::DEFAULT_COLUMN_FAMILY_NAME, db_opts.clone()),
+            ColumnFamilyDescriptor::new(ETAG_CF, Options::default()),
+            ColumnFamilyDescriptor::new(NTLM_CF, db_opts.clone()),
+        ];
+
+        let inner = if opt.open_read_only {
+            DB::open_cf_descriptors_read_only(&db_opts, &opt.db, cfs, false)?
+        } else {
+            DB::open_cf_descriptors(&db_opts, &opt.db, cfs)?
+        };
 
         Ok(Database { inner })
     }
 
-    fn set(&self, hash: PasswordHash, n: u32) -> Result<(), rocksdb::Error> {
-        self.inner.put(hash.bytes, n.to_be_bytes())
+    fn etag_cf(&self) -> &ColumnFamily {
+        self.inner.cf_handle(ETAG_CF).expect("etag column family")
+    }
+
+    fn etag_key(kind: HashKind, prefix: PasswordHashPrefix) -> [u8; 4] {
+        let mut key = [0; 4];
+        key[0] = kind as u8;
+        key[1..].copy_from_slice(&prefix.key_bytes());
+        key
     }
 
-    fn get(&self, hash: PasswordHash) -> Result<u32, rocksdb::Error> {
+    fn get_etag(
+        &self,
+        kind: HashKind,
+        prefix: PasswordHashPrefix,
+    ) -> Result<Option<String>, rocksdb::Error> {
         Ok(self
             .inner
-            .get(hash.bytes)?
+            .get_cf(self.etag_cf(), Self::etag_key(kind, prefix))?
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    fn set_etag(
+        &self,
+        kind: HashKind,
+        prefix: PasswordHashPrefix,
+        etag: &str,
+    ) -> Result<(), rocksdb::Error> {
+        self.inner.put_cf(
+            self.etag_cf(),
+            Self::etag_key(kind, prefix),
+            etag.as_bytes(),
+        )
+    }
+
+    fn cf(&self, kind: HashKind) -> &ColumnFamily {
+        self.inner
+            .cf_handle(kind.cf_name())
+            .expect("hash kind column family")
+    }
+
+    fn set(&self, kind: HashKind, bytes: &[u8], n: u32) -> Result<(), rocksdb::Error> {
+        self.inner.put_cf(self.cf(kind), bytes, n.to_be_bytes())
+    }
+
+    fn get(&self, kind: HashKind, bytes: &[u8]) -> Result<u32, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_cf(self.cf(kind), bytes)?
             .map_or(0, |bytes| bytes.try_into().map_or(0, u32::from_be_bytes)))
     }
 
-    fn estimate_count(&self) -> Result<u64, rocksdb::Error> {
+    fn estimate_count(&self, kind: HashKind) -> Result<u64, rocksdb::Error> {
         Ok(self
             .inner
-            .property_int_value(ESTIMATE_NUM_KEYS)?
+            .property_int_value_cf(self.cf(kind), ESTIMATE_NUM_KEYS)?
             .unwrap_or(0))
     }
 
+    fn range(&self, kind: HashKind, prefix: PasswordHashPrefix) -> Vec<(String, u32)> {
+        let prefix_hex = prefix.to_hex_string();
+        let zeros = "0".repeat(kind.hash_byte_len() * 2 - prefix_hex.len());
+        let mut lower_bound = vec![0; kind.hash_byte_len()];
+        hex::decode_to_slice(format!("{prefix_hex}{zeros}"), &mut lower_bound[..])
+            .expect("lower bound hash");
+
+        let mut out = Vec::new();
+        for item in self.inner.iterator_cf(
+            self.cf(kind),
+            IteratorMode::From(&lower_bound, Direction::Forward),
+        ) {
+            let (key, value) = item.expect("rocksdb iterator");
+            let key_hex = hex::encode_upper(&key);
+            if key_hex[..5] != prefix_hex {
+                break;
+            }
+            let n = value.as_ref().try_into().map_or(0, u32::from_be_bytes);
+            out.push((key_hex[5..].to_string(), n));
+        }
+        out
+    }
+
+    fn replace_range<H: AsRef<[u8]>>(
+        &self,
+        kind: HashKind,
+        prefix: PasswordHashPrefix,
+        entries: &[(H, u32)],
+    ) -> Result<(), rocksdb::Error> {
+        let wanted: HashSet<String> = entries
+            .iter()
+            .map(|(hash, _)| hex::encode_upper(hash.as_ref())[5..].to_string())
+            .collect();
+
+        for (suffix, _) in self.range(kind, prefix) {
+            if !wanted.contains(&suffix) {
+                let mut hex_hash = prefix.to_hex_string();
+                hex_hash.push_str(&suffix);
+                let mut bytes = vec![0; kind.hash_byte_len()];
+                hex::decode_to_slice(&hex_hash, &mut bytes[..]).expect("hash round trip");
+                self.inner.delete_cf(self.cf(kind), &bytes)?;
+            }
+        }
+
+        for (hash, n) in entries {
+            if *n > 0 {
+                self.inner
+                    .put_cf(self.cf(kind), hash.as_ref(), n.to_be_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn compact(&self) {
         self.inner.compact_range(None::<&[u8]>, None::<&[u8]>);
     }
+
+    fn iter_raw(
+        &self,
+        kind: HashKind,
+    ) -> impl Iterator<Item = Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>> + '_ {
+        self.inner.iterator_cf(self.cf(kind), IteratorMode::Start)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -120,6 +322,39 @@ impl FromStr for PasswordHash {
     }
 }
 
+impl PasswordHash {
+    fn suffix_hex(&self) -> String {
+        hex::encode_upper(self.bytes)[5..].to_string()
+    }
+}
+
+impl AsRef<[u8]> for PasswordHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct NtlmHash {
+    bytes: [u8; 16],
+}
+
+impl FromStr for NtlmHash {
+    type Err = InvalidPasswordHash;
+
+    fn from_str(s: &str) -> Result<NtlmHash, InvalidPasswordHash> {
+        let mut bytes = [0; 16];
+        hex::decode_to_slice(s, &mut bytes[..])?;
+        Ok(NtlmHash { bytes })
+    }
+}
+
+impl AsRef<[u8]> for NtlmHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct PasswordHashPrefix(u32);
 
@@ -130,6 +365,29 @@ impl PasswordHashPrefix {
         let s = hex::encode_upper(self.0.to_be_bytes());
         s[3..].to_string()
     }
+
+    fn key_bytes(&self) -> [u8; 3] {
+        let mut bytes = [0; 3];
+        hex::decode_to_slice(format!("{}0", self.to_hex_string()), &mut bytes[..])
+            .expect("prefix hex is always valid");
+        bytes
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid password hash prefix: {0:?}")]
+struct InvalidPasswordHashPrefix(String);
+
+impl FromStr for PasswordHashPrefix {
+    type Err = InvalidPasswordHashPrefix;
+
+    fn from_str(s: &str) -> Result<PasswordHashPrefix, InvalidPasswordHashPrefix> {
+        if s.len() != 5 {
+            return Err(InvalidPasswordHashPrefix(s.to_owned()));
+        }
+        let n = u32::from_str_radix(s, 16).map_err(|_| InvalidPasswordHashPrefix(s.to_owned()))?;
+        Ok(PasswordHashPrefix(n))
+    }
 }
 
 #[tokio::main]
@@ -140,8 +398,8 @@ async fn main() {
 
     let db: &'static Database = Box::leak(Box::new(Database::open(&opt).expect("open database")));
 
-    for source in opt.source {
-        load(db, &source).expect("open source");
+    for source in &opt.source {
+        load(db, source, opt.hash_type).expect("open source");
     }
 
     if opt.compact {
@@ -149,24 +407,44 @@ async fn main() {
         db.compact();
     }
 
+    if let Some(ref export_path) = opt.export {
+        export(db, export_path, opt.hash_type).expect("export");
+    }
+
+    if opt.verify && !verify(db, opt.hash_type) {
+        std::process::exit(1);
+    }
+
     if opt.upstream_update {
-        tokio::spawn(upstream_update_forever(db));
+        tokio::spawn(upstream_update_forever(
+            db,
+            opt.update_concurrency,
+            opt.update_interval,
+            opt.update_rate_limit,
+            opt.hash_type,
+        ));
     }
 
     if let Some(ref bind) = opt.bind {
         log::info!("Serving at {:?} ...", bind);
 
+        let state = AppState {
+            db,
+            hash_type: opt.hash_type,
+        };
+
         let app = Router::new()
             .route("/status", get(status))
             .route("/", get(query))
-            .with_state(db);
+            .route("/range/{prefix}", get(range))
+            .with_state(state);
 
         let listener = TcpListener::bind(bind).await.expect("bind");
         axum::serve(listener, app).await.expect("serve");
     }
 }
 
-fn load(db: &Database, path: &Path) -> io::Result<()> {
+fn load(db: &Database, path: &Path, hash_type: HashKind) -> io::Result<()> {
     let file = File::open(path)?;
 
     let file = ProgressBar::with_draw_target(
@@ -201,12 +479,21 @@ fn load(db: &Database, path: &Path) -> io::Result<()> {
             }
         };
 
-        let hash = match hash.parse() {
-            Ok(hash) => hash,
-            Err(err) => {
-                log::warn!("{err}: {line}");
-                continue;
-            }
+        let bytes: Vec<u8> = match hash_type {
+            HashKind::Sha1 => match hash.parse::<PasswordHash>() {
+                Ok(hash) => hash.bytes.to_vec(),
+                Err(err) => {
+                    log::warn!("{err}: {line}");
+                    continue;
+                }
+            },
+            HashKind::Ntlm => match hash.parse::<NtlmHash>() {
+                Ok(hash) => hash.bytes.to_vec(),
+                Err(err) => {
+                    log::warn!("{err}: {line}");
+                    continue;
+                }
+            },
         };
         let n = match n.parse() {
             Ok(n) => n,
@@ -216,12 +503,77 @@ fn load(db: &Database, path: &Path) -> io::Result<()> {
             }
         };
 
-        db.set(hash, n).expect("db set for load");
+        db.set(hash_type, &bytes, n).expect("db set for load");
     }
 
     Ok(())
 }
 
+fn export(db: &Database, path: &Path, hash_type: HashKind) -> io::Result<()> {
+    let file = File::create(path)?;
+
+    let progress = ProgressBar::with_draw_target(
+        Some(db.estimate_count(hash_type).expect("estimate count")),
+        ProgressDrawTarget::stdout_with_hz(4),
+    )
+    .with_style(
+        ProgressStyle::with_template("{spinner} {prefix} {msg} {wide_bar} {pos}/{len} {eta:>7}")
+            .unwrap(),
+    )
+    .with_prefix(format!("{path:?}"));
+
+    let mut writer: Box<dyn io::Write> = if path.extension().map_or(false, |ext| ext == "zst") {
+        log::info!("Exporting compressed {:?} ...", path);
+        Box::new(zstd::Encoder::new(file, 0)?.auto_finish())
+    } else {
+        log::info!("Exporting plain text {:?} ...", path);
+        Box::new(file)
+    };
+
+    for item in db.iter_raw(hash_type) {
+        let (key, value) = item.expect("rocksdb iterator");
+        let hash = hex::encode_upper(&key);
+        let n: u32 = value.as_ref().try_into().map_or(0, u32::from_be_bytes);
+        writeln!(writer, "{hash}:{n}")?;
+        progress.inc(1);
+    }
+
+    progress.finish();
+
+    Ok(())
+}
+
+fn verify(db: &Database, hash_type: HashKind) -> bool {
+    let progress = ProgressBar::with_draw_target(
+        Some(db.estimate_count(hash_type).expect("estimate count")),
+        ProgressDrawTarget::stdout_with_hz(4),
+    )
+    .with_style(
+        ProgressStyle::with_template("{spinner} {prefix} {msg} {wide_bar} {pos}/{len} {eta:>7}")
+            .unwrap(),
+    )
+    .with_prefix("verify");
+
+    let mut corrupt = 0u64;
+    for item in db.iter_raw(hash_type) {
+        let (_key, value) = item.expect("rocksdb iterator");
+        if value.len() != 4 {
+            corrupt += 1;
+        }
+        progress.inc(1);
+    }
+
+    progress.finish();
+
+    if corrupt > 0 {
+        log::error!("Verify: found {corrupt} corrupt/short entries");
+        false
+    } else {
+        log::info!("Verify: all entries OK");
+        true
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("upstream error: {0}")]
 enum UpstreamError {
@@ -230,10 +582,10 @@ enum UpstreamError {
     ReqwestError(#[from] reqwest::Error),
 }
 
-fn parse_upstream_range(
+fn parse_upstream_range<H: FromStr<Err = InvalidPasswordHash>>(
     prefix: PasswordHashPrefix,
     body: &str,
-) -> Result<Vec<(PasswordHash, u32)>, UpstreamError> {
+) -> Result<Vec<(H, u32)>, UpstreamError> {
     let mut out = Vec::with_capacity(body.len() / 35);
     for line in body.lines() {
         let (suffix, n) = line
@@ -254,47 +606,116 @@ fn parse_upstream_range(
     Ok(out)
 }
 
-async fn upstream_update_range(
+fn upstream_range_url(kind: HashKind, prefix: PasswordHashPrefix) -> String {
+    match kind {
+        HashKind::Sha1 => format!(
+            "https://api.pwnedpasswords.com/range/{}",
+            prefix.to_hex_string()
+        ),
+        HashKind::Ntlm => format!(
+            "https://api.pwnedpasswords.com/range/{}?mode=ntlm",
+            prefix.to_hex_string()
+        ),
+    }
+}
+
+async fn upstream_update_range<H: AsRef<[u8]> + FromStr<Err = InvalidPasswordHash>>(
     db: &Database,
     client: &reqwest::Client,
+    kind: HashKind,
     prefix: PasswordHashPrefix,
 ) -> Result<(), UpstreamError> {
-    let body = client
-        .get(format!(
-            "https://api.pwnedpasswords.com/range/{}",
-            prefix.to_hex_string()
-        ))
-        .send()
-        .await?
-        .text()
-        .await?;
+    let mut request = client
+        .get(upstream_range_url(kind, prefix))
+        .header("Add-Padding", "true");
+
+    if let Some(etag) = db
+        .get_etag(kind, prefix)
+        .expect("db get etag for upstream update")
+    {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+
+    let response = request.send().await?;
+
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        log::debug!("Upstream update: {} not modified", prefix.to_hex_string());
+        return Ok(());
+    }
+
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    let body = response.text().await?;
 
-    let out = parse_upstream_range(prefix, &body)?;
+    let out: Vec<(H, u32)> = parse_upstream_range(prefix, &body)?;
     log::debug!(
         "Upstream update: Received {} records for prefix {}",
         out.len(),
         prefix.to_hex_string()
     );
 
-    for (hash, n) in parse_upstream_range(prefix, &body)? {
-        if n > 0 {
-            db.set(hash, n).expect("db set for upstream update");
-        }
+    db.replace_range(kind, prefix, &out)
+        .expect("db replace range for upstream update");
+
+    if let Some(etag) = etag {
+        db.set_etag(kind, prefix, &etag)
+            .expect("db set etag for upstream update");
     }
 
     Ok(())
 }
 
-async fn upstream_update_forever(db: &Database) {
+/// Caps the overall dispatch rate of upstream requests to a minimum interval
+/// between successive sends, independent of how many are in flight at once.
+struct RateLimiter {
+    min_interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(min_interval: Duration) -> RateLimiter {
+        RateLimiter {
+            min_interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) {
+        let scheduled = {
+            let mut next = self.next.lock().await;
+            let scheduled = (*next).max(Instant::now());
+            *next = scheduled + self.min_interval;
+            scheduled
+        };
+        sleep_until(scheduled).await;
+    }
+}
+
+async fn upstream_update_forever(
+    db: &'static Database,
+    concurrency: usize,
+    interval: Duration,
+    rate_limit: Duration,
+    hash_type: HashKind,
+) {
     let client = reqwest::Client::builder()
         .user_agent("lila-pwned")
         .timeout(Duration::from_secs(10))
         .build()
         .expect("client");
 
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let limiter = Arc::new(RateLimiter::new(rate_limit));
+
     loop {
         log::info!("Beginning new upstream update ...");
 
+        let mut tasks = JoinSet::new();
+
         for prefix in 0..=PasswordHashPrefix::MAX.0 {
             let prefix = PasswordHashPrefix(prefix);
 
@@ -302,29 +723,60 @@ async fn upstream_update_forever(db: &Database) {
                 log::info!(
                     "Upstream update: At prefix {} (currently {} local records estimated)",
                     prefix.to_hex_string(),
-                    db.estimate_count().expect("estimate count")
+                    db.estimate_count(hash_type).expect("estimate count")
                 );
             }
 
-            if let Err(err) = upstream_update_range(db, &client, prefix).await {
-                log::error!("{} at {}", err, prefix.to_hex_string());
-            }
-
-            sleep(Duration::from_secs(2)).await;
+            let semaphore = Arc::clone(&semaphore);
+            let limiter = Arc::clone(&limiter);
+            let client = client.clone();
+
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await.expect("semaphore closed");
+                limiter.acquire().await;
+                let result = match hash_type {
+                    HashKind::Sha1 => {
+                        upstream_update_range::<PasswordHash>(db, &client, hash_type, prefix).await
+                    }
+                    HashKind::Ntlm => {
+                        upstream_update_range::<NtlmHash>(db, &client, hash_type, prefix).await
+                    }
+                };
+                if let Err(err) = result {
+                    log::error!("{} at {}", err, prefix.to_hex_string());
+                }
+            });
         }
+
+        while tasks.join_next().await.is_some() {}
+
+        sleep(interval).await;
     }
 }
 
-async fn status(State(db): State<&'static Database>) -> String {
-    let count = db.estimate_count().expect("estimate count");
+#[derive(Copy, Clone)]
+struct AppState {
+    db: &'static Database,
+    hash_type: HashKind,
+}
+
+async fn status(State(state): State<AppState>) -> String {
+    let count = state
+        .db
+        .estimate_count(state.hash_type)
+        .expect("estimate count");
     format!("pwned count={count}u")
 }
 
 #[serde_as]
 #[derive(Deserialize)]
 struct Params {
-    #[serde_as(as = "DisplayFromStr")]
-    sha1: PasswordHash,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    sha1: Option<PasswordHash>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    ntlm: Option<NtlmHash>,
 }
 
 #[derive(Serialize)]
@@ -332,10 +784,45 @@ struct Response {
     n: u32,
 }
 
-async fn query(State(db): State<&'static Database>, Query(query): Query<Params>) -> Json<Response> {
-    Json(Response {
-        n: db.get(query.sha1).expect("db get"),
-    })
+async fn query(
+    State(state): State<AppState>,
+    Query(query): Query<Params>,
+) -> Result<Json<Response>, StatusCode> {
+    let n = match (query.sha1, query.ntlm) {
+        (Some(hash), None) => state.db.get(HashKind::Sha1, &hash.bytes).expect("db get"),
+        (None, Some(hash)) => state.db.get(HashKind::Ntlm, &hash.bytes).expect("db get"),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    Ok(Json(Response { n }))
+}
+
+const RANGE_PADDING_MULTIPLE: usize = 100;
+
+fn pad_range(mut out: Vec<(String, u32)>) -> Vec<(String, u32)> {
+    let target = out.len().div_ceil(RANGE_PADDING_MULTIPLE).max(1) * RANGE_PADDING_MULTIPLE;
+    for i in out.len()..target {
+        out.push((format!("{i:035X}"), 0));
+    }
+    out
+}
+
+async fn range(
+    State(state): State<AppState>,
+    AxumPath(prefix): AxumPath<String>,
+    headers: HeaderMap,
+) -> Result<String, StatusCode> {
+    let prefix: PasswordHashPrefix = prefix.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let mut out = state.db.range(HashKind::Sha1, prefix);
+    if headers.get("Add-Padding").is_some_and(|v| v == "true") {
+        out = pad_range(out);
+    }
+
+    Ok(out
+        .into_iter()
+        .map(|(suffix, n)| format!("{suffix}:{n}"))
+        .collect::<Vec<_>>()
+        .join("\n"))
 }
 
 #[cfg(test)]
@@ -353,6 +840,61 @@ mod tests {
         assert_eq!(PasswordHashPrefix::MAX.to_hex_string(), "FFFFF");
     }
 
+    #[test]
+    fn test_password_hash_prefix_from_str() {
+        assert_eq!("ABCDE".parse::<PasswordHashPrefix>().unwrap().0, 0xabcde);
+        assert_eq!("00000".parse::<PasswordHashPrefix>().unwrap().0, 0);
+        assert!("ABCD".parse::<PasswordHashPrefix>().is_err());
+        assert!("ABCDEF".parse::<PasswordHashPrefix>().is_err());
+        assert!("GHIJK".parse::<PasswordHashPrefix>().is_err());
+    }
+
+    #[test]
+    fn test_password_hash_prefix_key_bytes() {
+        assert_eq!(PasswordHashPrefix(0xabcde).key_bytes(), [0xab, 0xcd, 0xe0]);
+        assert_eq!(PasswordHashPrefix(0).key_bytes(), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_password_hash_suffix_hex() {
+        let hash = PasswordHash {
+            bytes: [
+                0xab, 0xcd, 0xe0, 0x01, 0x8A, 0x45, 0xC4, 0xD1, 0xDE, 0xF8, 0x16, 0x44, 0xB5, 0x4A,
+                0xB7, 0xF9, 0x69, 0xB8, 0x8D, 0x65,
+            ],
+        };
+        assert_eq!(hash.suffix_hex(), "0018A45C4D1DEF81644B54AB7F969B88D65");
+    }
+
+    #[test]
+    fn test_ntlm_hash_from_str() {
+        let hash: NtlmHash = "B4B9B02E6F09A9BD760F388B67351E2B"
+            .parse()
+            .expect("parse ntlm hash");
+        assert_eq!(
+            hash.bytes,
+            [
+                0xB4, 0xB9, 0xB0, 0x2E, 0x6F, 0x09, 0xA9, 0xBD, 0x76, 0x0F, 0x38, 0x8B, 0x67, 0x35,
+                0x1E, 0x2B
+            ]
+        );
+        assert!("not hex".parse::<NtlmHash>().is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert!(parse_duration("not a duration").is_err());
+    }
+
+    #[test]
+    fn test_pad_range() {
+        let out = pad_range(vec![("A".repeat(35), 1), ("B".repeat(35), 2)]);
+        assert_eq!(out.len(), RANGE_PADDING_MULTIPLE);
+        assert_eq!(out[2], (format!("{:035X}", 0), 0));
+    }
+
     #[test]
     fn test_parse_upstream_range() {
         let body = "0018A45C4D1DEF81644B54AB7F969B88D65:1